@@ -9,14 +9,28 @@ use crate::conversation::history::ProcessRunLogger;
 use crate::llm::SpacebotModel;
 use crate::{AgentDeps, ProcessType};
 
+use chrono::{Duration as ChronoDuration, Utc};
+use futures::StreamExt;
 use rig::agent::AgentBuilder;
-use rig::completion::{AssistantContent, CompletionModel, Prompt};
+use rig::completion::{AssistantContent, CompletionModel};
+use rig::streaming::{StreamedAssistantContent, StreamingPrompt};
 use rig::tool::server::ToolServerHandle;
 use serde::Serialize;
 use sqlx::SqlitePool;
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration as StdDuration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{broadcast, Mutex};
+
+/// Capacity of each per-thread broadcast channel. Slow subscribers that fall
+/// this far behind a fast-streaming response will see `Lagged` and should
+/// fall back to reloading history.
+const BROADCAST_CAPACITY: usize = 256;
 
 /// A persisted cortex chat message.
 #[derive(Debug, Clone, Serialize)]
@@ -26,9 +40,54 @@ pub struct CortexChatMessage {
     pub role: String,
     pub content: String,
     pub channel_context: Option<String>,
+    pub seq: i64,
+    /// The message this one was forked or regenerated from, if any.
+    pub parent_id: Option<String>,
+    /// The caller's privilege tier at the time this message was produced,
+    /// for auditing which role a tool-bearing reply ran under.
+    pub admin_role: Option<String>,
     pub created_at: String,
 }
 
+/// Privilege tiers for cortex chat callers, ranked low to high. A caller may
+/// only invoke a tool when their role rank is `>=` the tool's required rank
+/// — the same hierarchy-comparison used by permission-checked bot commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    ReadOnly,
+    Operator,
+    Root,
+}
+
+impl AdminRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            AdminRole::ReadOnly => "read_only",
+            AdminRole::Operator => "operator",
+            AdminRole::Root => "root",
+        }
+    }
+}
+
+/// The minimum [`AdminRole`] required to invoke `tool_name`. Tools not
+/// listed here (memory, web search, ...) are available to every role.
+fn tool_capability(tool_name: &str) -> AdminRole {
+    match tool_name {
+        "shell" | "exec" => AdminRole::Root,
+        "file" | "browser" => AdminRole::Operator,
+        _ => AdminRole::ReadOnly,
+    }
+}
+
+/// Whether `role` may invoke `tool_name`. This is the exact predicate
+/// `scoped_tool_server` hands to `ToolServerHandle::filtered`, pulled out
+/// as a free function so the dispatch-time boundary itself is unit
+/// testable, not just the static [`tool_capability`] table behind it.
+fn dispatch_allowed(tool_name: &str, role: AdminRole) -> bool {
+    tool_capability(tool_name) <= role
+}
+
 /// Events emitted during a cortex chat response (sent via SSE to the client).
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -38,17 +97,49 @@ pub enum CortexChatEvent {
     /// A tool call started.
     ToolStarted { tool: String },
     /// A tool call completed.
-    ToolCompleted { tool: String, result_preview: String },
+    ToolCompleted {
+        tool: String,
+        result_preview: String,
+    },
+    /// The user message was saved (lets late subscribers see what triggered the run).
+    UserMessage { text: String },
+    /// A chunk of assistant text arrived.
+    TokenDelta { text: String },
     /// The full response is ready.
     Done { full_text: String },
     /// An error occurred.
     Error { message: String },
 }
 
+/// How long-lived cortex chat threads get trimmed so the DB doesn't grow
+/// unbounded. Applied per `thread_id` by the background pruning task.
+#[derive(Debug, Clone, Copy)]
+pub struct ChatRetentionPolicy {
+    /// Drop messages older than this, regardless of count.
+    pub max_age: StdDuration,
+    /// Keep at most this many messages per thread.
+    pub max_per_thread: i64,
+    /// How often the background task sweeps the store.
+    pub sweep_interval: StdDuration,
+}
+
+impl Default for ChatRetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: StdDuration::from_secs(60 * 60 * 24 * 30),
+            max_per_thread: 2_000,
+            sweep_interval: StdDuration::from_secs(60 * 60),
+        }
+    }
+}
+
 /// SQLite CRUD for cortex chat messages.
 #[derive(Debug, Clone)]
 pub struct CortexChatStore {
     pool: SqlitePool,
+    /// Clock-skew-proof counter for `seq`. Seeded from `MAX(seq)` at
+    /// construction time so ordering survives restarts.
+    last_seen: Arc<AtomicI64>,
 }
 
 #[derive(sqlx::FromRow)]
@@ -58,6 +149,9 @@ struct ChatMessageRow {
     role: String,
     content: String,
     channel_context: Option<String>,
+    seq: i64,
+    parent_id: Option<String>,
+    admin_role: Option<String>,
     created_at: chrono::NaiveDateTime,
 }
 
@@ -69,25 +163,106 @@ impl ChatMessageRow {
             role: self.role,
             content: self.content,
             channel_context: self.channel_context,
+            seq: self.seq,
+            parent_id: self.parent_id,
+            admin_role: self.admin_role,
             created_at: self.created_at.and_utc().to_rfc3339(),
         }
     }
 }
 
 impl CortexChatStore {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+    /// Open the store, seeding the monotonic sequence counter from the
+    /// highest `seq` already on disk.
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        let max_seq: Option<i64> = sqlx::query_scalar("SELECT MAX(seq) FROM cortex_chat_messages")
+            .fetch_one(&pool)
+            .await?;
+
+        Ok(Self {
+            pool,
+            last_seen: Arc::new(AtomicI64::new(max_seq.unwrap_or(0))),
+        })
     }
 
-    /// Load chat history for a thread, newest first, then reverse to chronological order.
-    pub async fn load_history(
+    /// Allocate the next monotonic `seq`. Clock-skew-proof: falls back to
+    /// `last_seen + 1` whenever wall-clock nanos would not move forward.
+    fn next_seq(&self) -> i64 {
+        loop {
+            let last = self.last_seen.load(Ordering::SeqCst);
+            let now = Utc::now().timestamp_nanos_opt().unwrap_or(last + 1);
+            let candidate = if now <= last { last + 1 } else { now };
+            if self
+                .last_seen
+                .compare_exchange(last, candidate, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return candidate;
+            }
+        }
+    }
+
+    /// Load chat history for a thread, newest first, then reverse to
+    /// chronological order. When the thread's earliest message carries a
+    /// `parent_id` (it was forked off another message), that ancestor's own
+    /// history — walked recursively, since a fork can itself be forked from
+    /// another fork — is prepended so a branched thread reconstructs the
+    /// full ancestral context for the Rig `history` vector. `fork_thread`
+    /// only ever copies the single forked-from message into the new thread,
+    /// so the ancestor cutoff is strict (`< parent.seq`): the parent itself
+    /// is represented by the copy already in `messages`, not the original.
+    pub fn load_history(
+        &self,
+        thread_id: &str,
+        limit: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<CortexChatMessage>, sqlx::Error>> + Send + '_>>
+    {
+        let thread_id = thread_id.to_string();
+        Box::pin(async move {
+            let mut messages = self.load_own_history(&thread_id, limit).await?;
+
+            if let Some(parent_id) = messages.first().and_then(|m| m.parent_id.clone()) {
+                match self.get_message(&parent_id).await? {
+                    Some(parent) => {
+                        let mut ancestors = self.load_history(&parent.thread_id, limit).await?;
+                        ancestors.retain(|m| m.seq < parent.seq);
+                        ancestors.extend(messages);
+                        messages = ancestors;
+                    }
+                    // `prune_thread`/`prune_older_than` exempt referenced
+                    // parents, but `regenerate`'s truncation does not, so a
+                    // fork's parent can still go missing; surface it instead
+                    // of silently truncating the forked thread's ancestral
+                    // context.
+                    None => {
+                        tracing::warn!(
+                            thread_id,
+                            parent_id,
+                            "cortex chat fork's ancestor message is missing; \
+                             history will start from the fork point"
+                        );
+                    }
+                }
+            }
+
+            let len = messages.len();
+            if len as i64 > limit {
+                messages.drain(0..(len - limit as usize));
+            }
+            Ok(messages)
+        })
+    }
+
+    /// `load_history` restricted to a single thread's own rows, with no
+    /// parent-chain following.
+    async fn load_own_history(
         &self,
         thread_id: &str,
         limit: i64,
     ) -> Result<Vec<CortexChatMessage>, sqlx::Error> {
         let rows: Vec<ChatMessageRow> = sqlx::query_as(
-            "SELECT id, thread_id, role, content, channel_context, created_at \
-             FROM cortex_chat_messages WHERE thread_id = ? ORDER BY created_at DESC LIMIT ?",
+            "SELECT id, thread_id, role, content, channel_context, seq, parent_id, admin_role, created_at \
+             FROM cortex_chat_messages WHERE thread_id = ? ORDER BY seq DESC LIMIT ?",
         )
         .bind(thread_id)
         .bind(limit)
@@ -100,6 +275,18 @@ impl CortexChatStore {
         Ok(messages)
     }
 
+    /// Fetch a single message by id, regardless of thread.
+    pub async fn get_message(&self, id: &str) -> Result<Option<CortexChatMessage>, sqlx::Error> {
+        let row: Option<ChatMessageRow> = sqlx::query_as(
+            "SELECT id, thread_id, role, content, channel_context, seq, parent_id, admin_role, created_at \
+             FROM cortex_chat_messages WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(ChatMessageRow::into_message))
+    }
+
     /// Save a message to a thread. Returns the generated ID.
     pub async fn save_message(
         &self,
@@ -107,30 +294,240 @@ impl CortexChatStore {
         role: &str,
         content: &str,
         channel_context: Option<&str>,
+    ) -> Result<String, sqlx::Error> {
+        self.save_reply(thread_id, role, content, channel_context, None, None)
+            .await
+    }
+
+    /// Like [`Self::save_message`], but records which message (possibly in
+    /// another thread) this one was forked or regenerated from, and which
+    /// [`AdminRole`] the caller held when it was produced.
+    pub async fn save_reply(
+        &self,
+        thread_id: &str,
+        role: &str,
+        content: &str,
+        channel_context: Option<&str>,
+        parent_id: Option<&str>,
+        admin_role: Option<&str>,
     ) -> Result<String, sqlx::Error> {
         let id = uuid::Uuid::new_v4().to_string();
+        let seq = self.next_seq();
         sqlx::query(
-            "INSERT INTO cortex_chat_messages (id, thread_id, role, content, channel_context) \
-             VALUES (?, ?, ?, ?, ?)",
+            "INSERT INTO cortex_chat_messages \
+             (id, thread_id, role, content, channel_context, seq, parent_id, admin_role) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
         )
         .bind(&id)
         .bind(thread_id)
         .bind(role)
         .bind(content)
         .bind(channel_context)
+        .bind(seq)
+        .bind(parent_id)
+        .bind(admin_role)
         .execute(&self.pool)
         .await?;
         Ok(id)
     }
 
+    /// Branch a new thread off `from_message_id`, copying only that single
+    /// message into a fresh row under a new `thread_id`, tagged with
+    /// `parent_id = from_message_id`. The rest of the ancestor chain is not
+    /// copied — `load_history` walks `parent_id` recursively to reconstruct
+    /// it, so copying the whole chain here would make every ancestor show
+    /// up twice. Returns the new thread id.
+    pub async fn fork_thread(&self, from_message_id: &str) -> Result<String, sqlx::Error> {
+        let root = self
+            .get_message(from_message_id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        let new_thread_id = uuid::Uuid::new_v4().to_string();
+
+        self.save_reply(
+            &new_thread_id,
+            &root.role,
+            &root.content,
+            root.channel_context.as_deref(),
+            Some(&root.id),
+            root.admin_role.as_deref(),
+        )
+        .await?;
+
+        Ok(new_thread_id)
+    }
+
+    /// Truncate `thread_id` to `from_message_id`, optionally replacing its
+    /// content, so the caller can re-invoke the agent from that point.
+    pub async fn regenerate(
+        &self,
+        thread_id: &str,
+        from_message_id: &str,
+        edited_text: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let from_message = self
+            .get_message(from_message_id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        // `from_message_id` is looked up by id alone, so a caller passing a
+        // message that actually lives in a different thread must not be
+        // allowed to edit/truncate here — otherwise the UPDATE and DELETE
+        // below would mutate the wrong thread using a foreign seq cutoff.
+        if from_message.thread_id != thread_id {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        if let Some(text) = edited_text {
+            sqlx::query("UPDATE cortex_chat_messages SET content = ? WHERE id = ?")
+                .bind(text)
+                .bind(from_message_id)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM cortex_chat_messages WHERE thread_id = ? AND seq > ?")
+            .bind(thread_id)
+            .bind(from_message.seq)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
     /// Get the most recent thread_id, or None if no threads exist.
     pub async fn latest_thread_id(&self) -> Result<Option<String>, sqlx::Error> {
-        let row: Option<(String,)> = sqlx::query_as(
-            "SELECT thread_id FROM cortex_chat_messages ORDER BY created_at DESC LIMIT 1",
+        let row: Option<(String,)> =
+            sqlx::query_as("SELECT thread_id FROM cortex_chat_messages ORDER BY seq DESC LIMIT 1")
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|r| r.0))
+    }
+
+    /// Keep only the most recent `keep_last` messages in `thread_id`,
+    /// deleting anything older. Rows a forked thread still points at via
+    /// `parent_id` are left alone — `load_history` depends on them to
+    /// reconstruct ancestor context, and deleting one would silently break
+    /// every thread forked from it.
+    pub async fn prune_thread(&self, thread_id: &str, keep_last: i64) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM cortex_chat_messages WHERE thread_id = ? AND seq NOT IN \
+             (SELECT seq FROM cortex_chat_messages WHERE thread_id = ? ORDER BY seq DESC LIMIT ?) \
+             AND id NOT IN (SELECT parent_id FROM cortex_chat_messages WHERE parent_id IS NOT NULL)",
+        )
+        .bind(thread_id)
+        .bind(thread_id)
+        .bind(keep_last)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete every message older than `max_age`, across all threads. Rows
+    /// referenced by a forked thread's `parent_id` are exempt — see
+    /// [`Self::prune_thread`].
+    pub async fn prune_older_than(&self, max_age: ChronoDuration) -> Result<u64, sqlx::Error> {
+        let cutoff = (Utc::now() - max_age).naive_utc();
+        let result = sqlx::query(
+            "DELETE FROM cortex_chat_messages WHERE created_at < ? \
+             AND id NOT IN (SELECT parent_id FROM cortex_chat_messages WHERE parent_id IS NOT NULL)",
+        )
+        .bind(cutoff)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Record that `client_id` has seen up through `seq` in `thread_id`.
+    /// A no-op if the client's cursor is already past `seq` (out-of-order
+    /// acks from a client with several in-flight reads shouldn't rewind it).
+    pub async fn mark_seen(
+        &self,
+        client_id: &str,
+        thread_id: &str,
+        seq: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO cortex_chat_read_cursors (client_id, thread_id, last_seen_seq) \
+             VALUES (?, ?, ?) \
+             ON CONFLICT(client_id, thread_id) DO UPDATE SET last_seen_seq = excluded.last_seen_seq \
+             WHERE excluded.last_seen_seq > cortex_chat_read_cursors.last_seen_seq",
+        )
+        .bind(client_id)
+        .bind(thread_id)
+        .bind(seq)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Messages in `thread_id` the client hasn't seen yet, chronological.
+    /// Lets a reconnecting SSE endpoint replay just the gap instead of the
+    /// whole transcript.
+    pub async fn load_unseen(
+        &self,
+        client_id: &str,
+        thread_id: &str,
+    ) -> Result<Vec<CortexChatMessage>, sqlx::Error> {
+        let last_seen_seq: i64 = sqlx::query_scalar(
+            "SELECT last_seen_seq FROM cortex_chat_read_cursors \
+             WHERE client_id = ? AND thread_id = ?",
         )
+        .bind(client_id)
+        .bind(thread_id)
         .fetch_optional(&self.pool)
+        .await?
+        .unwrap_or(0);
+
+        let rows: Vec<ChatMessageRow> = sqlx::query_as(
+            "SELECT id, thread_id, role, content, channel_context, seq, parent_id, admin_role, created_at \
+             FROM cortex_chat_messages WHERE thread_id = ? AND seq > ? ORDER BY seq ASC",
+        )
+        .bind(thread_id)
+        .bind(last_seen_seq)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(row.map(|r| r.0))
+
+        Ok(rows.into_iter().map(|row| row.into_message()).collect())
+    }
+
+    /// Spawn a background task that periodically applies `policy` to every
+    /// thread. Intended to be called once per store instance.
+    pub fn spawn_pruning_task(&self, policy: ChatRetentionPolicy) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(policy.sweep_interval);
+            loop {
+                interval.tick().await;
+
+                let thread_ids: Vec<String> = match sqlx::query_scalar(
+                    "SELECT DISTINCT thread_id FROM cortex_chat_messages",
+                )
+                .fetch_all(&store.pool)
+                .await
+                {
+                    Ok(ids) => ids,
+                    Err(error) => {
+                        tracing::warn!(%error, "failed to list threads for cortex chat pruning");
+                        continue;
+                    }
+                };
+
+                for thread_id in thread_ids {
+                    if let Err(error) = store.prune_thread(&thread_id, policy.max_per_thread).await
+                    {
+                        tracing::warn!(%error, thread_id, "failed to prune cortex chat thread by count");
+                    }
+                }
+
+                let max_age =
+                    ChronoDuration::from_std(policy.max_age).unwrap_or(ChronoDuration::zero());
+                if let Err(error) = store.prune_older_than(max_age).await {
+                    tracing::warn!(%error, "failed to prune cortex chat history by age");
+                }
+            }
+        });
     }
 }
 
@@ -143,22 +540,68 @@ pub struct CortexChatSession {
     pub store: CortexChatStore,
     /// Prevent concurrent sends — only one request at a time per agent.
     send_lock: Mutex<()>,
+    /// Per-thread fan-out so every open tab watching a `thread_id` sees the
+    /// same events, mirroring the broadcast pattern used for channel chat.
+    subscribers: Mutex<HashMap<String, broadcast::Sender<CortexChatEvent>>>,
 }
 
 impl CortexChatSession {
     pub fn new(deps: AgentDeps, tool_server: ToolServerHandle, store: CortexChatStore) -> Self {
+        store.spawn_pruning_task(ChatRetentionPolicy::default());
         Self {
             deps,
             tool_server,
             store,
             send_lock: Mutex::new(()),
+            subscribers: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Subscribe to live events for `thread_id`, creating its broadcast
+    /// channel if this is the first subscriber. A reconnecting or second
+    /// client can attach this way and see tool progress and the final
+    /// answer of an in-flight response.
+    pub async fn subscribe(&self, thread_id: &str) -> broadcast::Receiver<CortexChatEvent> {
+        self.channel_for(thread_id).await.subscribe()
+    }
+
+    async fn channel_for(&self, thread_id: &str) -> broadcast::Sender<CortexChatEvent> {
+        let mut subscribers = self.subscribers.lock().await;
+
+        // Every fork mints a fresh thread_id, so nothing but this sweep ever
+        // shrinks the map — drop entries nobody is listening to anymore
+        // before deciding whether `thread_id` needs a (re)created channel.
+        subscribers.retain(|_, sender| sender.receiver_count() > 0);
+
+        subscribers
+            .entry(thread_id.to_string())
+            .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
+            .clone()
+    }
+
+    /// Publish an event to every current subscriber of `thread_id`. A send
+    /// with no receivers is a normal no-op (nobody has the tab open).
+    async fn publish(&self, thread_id: &str, event: CortexChatEvent) {
+        let _ = self.channel_for(thread_id).await.send(event);
+    }
+
+    /// The tool server handle restricted to what `role` is allowed to
+    /// invoke. This is the server-side enforcement: a jailbroken model
+    /// cannot reach `shell`/`exec`/`file` by asking for them, because the
+    /// dispatcher never received the tool for a caller below its rank.
+    fn scoped_tool_server(&self, role: AdminRole) -> ToolServerHandle {
+        self.tool_server
+            .clone()
+            .filtered(move |tool_name| dispatch_allowed(tool_name, role))
+    }
+
     /// Send a message to the cortex chat and get the response.
     ///
-    /// This is the non-streaming version. The caller wraps this in SSE by
-    /// sending `thinking` -> running the call -> sending `done` / `error`.
+    /// This is the non-streaming version: `respond` still drives the agent
+    /// through the streaming Rig API internally. The events it emits still
+    /// reach `thread_id`'s broadcast subscribers, but the caller's direct
+    /// channel is a throwaway that nobody reads, and only the assembled text
+    /// is returned here.
     ///
     /// The channel_context_id is used to fetch recent channel history for
     /// injection into the system prompt.
@@ -167,21 +610,185 @@ impl CortexChatSession {
         thread_id: &str,
         user_text: &str,
         channel_context_id: Option<&str>,
+        admin_role: AdminRole,
     ) -> Result<String, anyhow::Error> {
         let _guard = self.send_lock.lock().await;
 
         // Save the user message
         self.store
-            .save_message(thread_id, "user", user_text, channel_context_id)
+            .save_reply(
+                thread_id,
+                "user",
+                user_text,
+                channel_context_id,
+                None,
+                Some(admin_role.as_str()),
+            )
+            .await?;
+        self.publish(
+            thread_id,
+            CortexChatEvent::UserMessage {
+                text: user_text.to_string(),
+            },
+        )
+        .await;
+
+        let (events, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.respond(
+            thread_id,
+            user_text,
+            channel_context_id,
+            admin_role,
+            &events,
+        )
+        .await
+    }
+
+    /// Edit a past user message and re-run the agent from that point, or
+    /// leave the content untouched to simply regenerate the reply that
+    /// followed it. Everything saved after `from_message_id` is discarded.
+    pub async fn regenerate(
+        &self,
+        thread_id: &str,
+        from_message_id: &str,
+        edited_text: Option<&str>,
+        channel_context_id: Option<&str>,
+        admin_role: AdminRole,
+    ) -> Result<String, anyhow::Error> {
+        let (events, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.regenerate_streaming(
+            thread_id,
+            from_message_id,
+            edited_text,
+            channel_context_id,
+            admin_role,
+            events,
+        )
+        .await
+    }
+
+    /// Streaming counterpart to [`Self::regenerate`]: same truncate-and-replay
+    /// semantics, but the replay is driven through `respond` so subscribers
+    /// get the same `Thinking`/`TokenDelta`/`ToolStarted`/`ToolCompleted`
+    /// stream that [`Self::send_message_streaming`] produces.
+    pub async fn regenerate_streaming(
+        &self,
+        thread_id: &str,
+        from_message_id: &str,
+        edited_text: Option<&str>,
+        channel_context_id: Option<&str>,
+        admin_role: AdminRole,
+        events: UnboundedSender<CortexChatEvent>,
+    ) -> Result<String, anyhow::Error> {
+        let _guard = self.send_lock.lock().await;
+
+        self.store
+            .regenerate(thread_id, from_message_id, edited_text)
+            .await?;
+
+        let prompt_text = match edited_text {
+            Some(text) => text.to_string(),
+            None => {
+                self.store
+                    .get_message(from_message_id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("message {from_message_id} not found"))?
+                    .content
+            }
+        };
+
+        self.respond(
+            thread_id,
+            &prompt_text,
+            channel_context_id,
+            admin_role,
+            &events,
+        )
+        .await
+    }
+
+    /// Branch a new thread off `from_message_id`. Returns the new thread id.
+    pub async fn fork_thread(&self, from_message_id: &str) -> Result<String, anyhow::Error> {
+        Ok(self.store.fork_thread(from_message_id).await?)
+    }
+
+    /// Send a message to the cortex chat and stream the response.
+    ///
+    /// Emits `ToolStarted`/`ToolCompleted`/`TokenDelta` events into `events`
+    /// as the agent runs, followed by a single `Done` once the response is
+    /// fully assembled, or `Error` on failure. The assistant message is
+    /// persisted exactly once at the end of the stream; on a mid-stream
+    /// failure whatever text had already accumulated is saved as a
+    /// best-effort partial so history stays consistent with `send_message`.
+    pub async fn send_message_streaming(
+        &self,
+        thread_id: &str,
+        user_text: &str,
+        channel_context_id: Option<&str>,
+        admin_role: AdminRole,
+        events: UnboundedSender<CortexChatEvent>,
+    ) -> Result<(), anyhow::Error> {
+        let _guard = self.send_lock.lock().await;
+
+        self.store
+            .save_reply(
+                thread_id,
+                "user",
+                user_text,
+                channel_context_id,
+                None,
+                Some(admin_role.as_str()),
+            )
             .await?;
+        self.emit(
+            thread_id,
+            &events,
+            CortexChatEvent::UserMessage {
+                text: user_text.to_string(),
+            },
+        )
+        .await;
+
+        self.respond(
+            thread_id,
+            user_text,
+            channel_context_id,
+            admin_role,
+            &events,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Run the agent against `prompt_text` using `thread_id`'s current
+    /// history (minus the message being responded to, which the caller has
+    /// already persisted), streaming every event through `events` as it
+    /// happens, then save and publish the final outcome. Shared by
+    /// `send_message`, `send_message_streaming`, `regenerate`, and
+    /// `regenerate_streaming` so role-gating, prompt-building, and event
+    /// semantics can't drift between the streaming and non-streaming paths.
+    /// Non-streaming callers pass a throwaway channel and read only the
+    /// returned text.
+    async fn respond(
+        &self,
+        thread_id: &str,
+        prompt_text: &str,
+        channel_context_id: Option<&str>,
+        admin_role: AdminRole,
+        events: &UnboundedSender<CortexChatEvent>,
+    ) -> Result<String, anyhow::Error> {
+        self.emit(thread_id, events, CortexChatEvent::Thinking)
+            .await;
 
         // Build the system prompt
-        let system_prompt = self.build_system_prompt(channel_context_id).await;
+        let system_prompt = self
+            .build_system_prompt(channel_context_id, admin_role)
+            .await;
 
         // Load chat history and convert to Rig messages
         let chat_messages = self.store.load_history(thread_id, 100).await?;
         let mut history: Vec<rig::message::Message> = Vec::new();
-        // Exclude the last message (the one we just saved) since we'll pass it as the prompt
+        // Exclude the last message (the one we're responding to) since we'll pass it as the prompt
         for message in &chat_messages[..chat_messages.len().saturating_sub(1)] {
             match message.role.as_str() {
                 "user" => {
@@ -204,48 +811,181 @@ impl CortexChatSession {
         let agent = AgentBuilder::new(model)
             .preamble(&system_prompt)
             .default_max_turns(50)
-            .tool_server_handle(self.tool_server.clone())
+            .tool_server_handle(self.scoped_tool_server(admin_role))
             .build();
 
-        // Run the agent
-        let result = agent
-            .prompt(user_text)
+        let mut stream = match agent
+            .stream_prompt(prompt_text)
             .with_history(&mut history)
-            .await;
-
-        match result {
-            Ok(response) => {
-                // Save the assistant response
-                self.store
-                    .save_message(thread_id, "assistant", &response, channel_context_id)
-                    .await?;
-                Ok(response)
-            }
+            .await
+        {
+            Ok(stream) => stream,
             Err(error) => {
                 let error_text = format!("Cortex chat error: {error}");
-                // Save error as assistant message so history stays consistent
                 self.store
-                    .save_message(thread_id, "assistant", &error_text, channel_context_id)
+                    .save_reply(
+                        thread_id,
+                        "assistant",
+                        &error_text,
+                        channel_context_id,
+                        None,
+                        Some(admin_role.as_str()),
+                    )
                     .await?;
-                Err(anyhow::anyhow!(error_text))
+                self.emit(
+                    thread_id,
+                    events,
+                    CortexChatEvent::Error {
+                        message: error_text.clone(),
+                    },
+                )
+                .await;
+                return Err(anyhow::anyhow!(error_text));
+            }
+        };
+
+        let mut full_text = String::new();
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(StreamedAssistantContent::Text(text)) => {
+                    full_text.push_str(&text.text);
+                    self.emit(
+                        thread_id,
+                        events,
+                        CortexChatEvent::TokenDelta { text: text.text },
+                    )
+                    .await;
+                }
+                Ok(StreamedAssistantContent::ToolCall(tool_call)) => {
+                    self.emit(
+                        thread_id,
+                        events,
+                        CortexChatEvent::ToolStarted {
+                            tool: tool_call.function.name.clone(),
+                        },
+                    )
+                    .await;
+                }
+                Ok(StreamedAssistantContent::ToolResult(tool_result)) => {
+                    self.emit(
+                        thread_id,
+                        events,
+                        CortexChatEvent::ToolCompleted {
+                            tool: tool_result.name.clone(),
+                            result_preview: tool_result.content_preview(),
+                        },
+                    )
+                    .await;
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    // Best-effort partial: persist whatever we managed to stream
+                    // so a mid-stream failure doesn't lose the in-flight reply.
+                    let error_text = format!("Cortex chat error: {error}");
+                    if !full_text.is_empty() {
+                        self.store
+                            .save_reply(
+                                thread_id,
+                                "assistant",
+                                &full_text,
+                                channel_context_id,
+                                None,
+                                Some(admin_role.as_str()),
+                            )
+                            .await?;
+                    }
+                    self.store
+                        .save_reply(
+                            thread_id,
+                            "assistant",
+                            &error_text,
+                            channel_context_id,
+                            None,
+                            Some(admin_role.as_str()),
+                        )
+                        .await?;
+                    self.emit(
+                        thread_id,
+                        events,
+                        CortexChatEvent::Error {
+                            message: error_text.clone(),
+                        },
+                    )
+                    .await;
+                    return Err(anyhow::anyhow!(error_text));
+                }
             }
         }
+
+        self.store
+            .save_reply(
+                thread_id,
+                "assistant",
+                &full_text,
+                channel_context_id,
+                None,
+                Some(admin_role.as_str()),
+            )
+            .await?;
+        self.emit(
+            thread_id,
+            events,
+            CortexChatEvent::Done {
+                full_text: full_text.clone(),
+            },
+        )
+        .await;
+        Ok(full_text)
+    }
+
+    /// Send an event to the caller's direct channel and fan it out to any
+    /// other subscribers of `thread_id`.
+    async fn emit(
+        &self,
+        thread_id: &str,
+        events: &UnboundedSender<CortexChatEvent>,
+        event: CortexChatEvent,
+    ) {
+        let _ = events.send(event.clone());
+        self.publish(thread_id, event).await;
     }
 
-    async fn build_system_prompt(&self, channel_context_id: Option<&str>) -> String {
+    async fn build_system_prompt(
+        &self,
+        channel_context_id: Option<&str>,
+        admin_role: AdminRole,
+    ) -> String {
         let runtime_config = &self.deps.runtime_config;
         let prompt_engine = runtime_config.prompts.load();
 
         let identity_context = runtime_config.identity.load().render();
         let memory_bulletin = runtime_config.memory_bulletin.load();
 
-        let browser_enabled = runtime_config.browser_config.load().enabled;
+        // Gate each flag by the caller's role so the preamble truthfully
+        // advertises only the tools `scoped_tool_server` actually hands over.
+        let shell_enabled = admin_role >= tool_capability("shell");
+        let file_enabled = admin_role >= tool_capability("file");
+        let browser_enabled = runtime_config.browser_config.load().enabled
+            && admin_role >= tool_capability("browser");
         let web_search_enabled = runtime_config.brave_search_key.load().is_some();
         let opencode_enabled = runtime_config.opencode.load().enabled;
         let worker_capabilities = prompt_engine
             .render_worker_capabilities(browser_enabled, web_search_enabled, opencode_enabled)
             .expect("failed to render worker capabilities");
 
+        // `render_worker_capabilities` only gates browser/web-search/opencode,
+        // so shell/file truthfulness is layered on here instead of growing
+        // that call's signature — this module doesn't own the prompt
+        // template, and the rendered text already documents shell/file as
+        // part of the "full toolset" regardless of role.
+        let shell_file_note = match (shell_enabled, file_enabled) {
+            (true, true) => "You also have shell and file access this session.",
+            (true, false) => "You also have shell access this session, but not file access.",
+            (false, true) => "You also have file access this session, but not shell access.",
+            (false, false) => "Shell and file access are not available this session.",
+        };
+        let worker_capabilities = format!("{worker_capabilities}\n\n{shell_file_note}");
+
         // Load channel transcript if a channel context is active
         let channel_transcript = if let Some(channel_id) = channel_context_id {
             self.load_channel_transcript(channel_id).await
@@ -300,8 +1040,7 @@ impl CortexChatSession {
                             ..
                         } => {
                             if let Some(result) = result {
-                                transcript
-                                    .push_str(&format!("*[Worker: {task}]*: {result}\n\n"));
+                                transcript.push_str(&format!("*[Worker: {task}]*: {result}\n\n"));
                             }
                         }
                     }
@@ -316,3 +1055,136 @@ impl CortexChatSession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_store() -> CortexChatStore {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            "CREATE TABLE cortex_chat_messages ( \
+                id TEXT PRIMARY KEY, \
+                thread_id TEXT NOT NULL, \
+                role TEXT NOT NULL, \
+                content TEXT NOT NULL, \
+                channel_context TEXT, \
+                seq INTEGER NOT NULL, \
+                parent_id TEXT, \
+                admin_role TEXT, \
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP \
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        CortexChatStore::new(pool).await.unwrap()
+    }
+
+    #[test]
+    fn admin_role_ranks_low_to_high() {
+        assert!(AdminRole::ReadOnly < AdminRole::Operator);
+        assert!(AdminRole::Operator < AdminRole::Root);
+    }
+
+    #[test]
+    fn tool_capability_matches_documented_tiers() {
+        assert_eq!(tool_capability("shell"), AdminRole::Root);
+        assert_eq!(tool_capability("exec"), AdminRole::Root);
+        assert_eq!(tool_capability("file"), AdminRole::Operator);
+        assert_eq!(tool_capability("browser"), AdminRole::Operator);
+        assert_eq!(tool_capability("memory"), AdminRole::ReadOnly);
+    }
+
+    #[test]
+    fn dispatch_allowed_rejects_below_tier_at_the_boundary_scoped_tool_server_uses() {
+        // `dispatch_allowed` is the exact predicate `scoped_tool_server` hands
+        // to `ToolServerHandle::filtered`, so this is what actually stands
+        // between a jailbroken model and `shell`/`exec`/`file` — not just the
+        // static table those tools are looked up in.
+        for tool in ["shell", "exec", "file"] {
+            assert!(
+                !dispatch_allowed(tool, AdminRole::ReadOnly),
+                "ReadOnly must not be able to dispatch {tool}"
+            );
+        }
+        assert!(!dispatch_allowed("shell", AdminRole::Operator));
+        assert!(!dispatch_allowed("exec", AdminRole::Operator));
+        assert!(dispatch_allowed("file", AdminRole::Operator));
+        assert!(dispatch_allowed("browser", AdminRole::Operator));
+
+        for tool in ["shell", "exec", "file", "browser", "memory"] {
+            assert!(
+                dispatch_allowed(tool, AdminRole::Root),
+                "Root must be able to dispatch {tool}"
+            );
+        }
+        assert!(dispatch_allowed("memory", AdminRole::ReadOnly));
+    }
+
+    #[tokio::test]
+    async fn next_seq_is_monotonic_under_concurrent_callers() {
+        let store = test_store().await;
+
+        let mut handles = Vec::new();
+        for _ in 0..50 {
+            let store = store.clone();
+            handles.push(tokio::spawn(async move { store.next_seq() }));
+        }
+
+        let mut seqs = Vec::new();
+        for handle in handles {
+            seqs.push(handle.await.unwrap());
+        }
+
+        let mut sorted = seqs.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), seqs.len(), "next_seq produced a duplicate");
+        assert!(
+            store.next_seq() > *sorted.last().unwrap(),
+            "next_seq went backwards"
+        );
+    }
+
+    #[tokio::test]
+    async fn regenerate_rejects_message_from_another_thread() {
+        let store = test_store().await;
+
+        store
+            .save_message("thread-a", "user", "hello from a", None)
+            .await
+            .unwrap();
+        let foreign_id = store
+            .save_message("thread-b", "user", "hello from b", None)
+            .await
+            .unwrap();
+
+        let result = store.regenerate("thread-a", &foreign_id, None).await;
+        assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+    }
+
+    #[tokio::test]
+    async fn fork_thread_reconstructs_history_without_duplicates() {
+        let store = test_store().await;
+
+        store
+            .save_message("thread-a", "user", "m1", None)
+            .await
+            .unwrap();
+        let m2 = store
+            .save_message("thread-a", "assistant", "m2", None)
+            .await
+            .unwrap();
+        store
+            .save_message("thread-a", "user", "m3", None)
+            .await
+            .unwrap();
+
+        let forked_thread = store.fork_thread(&m2).await.unwrap();
+        let history = store.load_history(&forked_thread, 100).await.unwrap();
+
+        let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["m1", "m2"]);
+    }
+}